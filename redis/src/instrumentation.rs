@@ -0,0 +1,29 @@
+use redis::RedisError;
+
+use std::time::Duration;
+
+/// Hooks for observing pool and connection lifecycle events.
+///
+/// Implement this to wire checkout latency, connection churn, and
+/// validation failures into metrics (Prometheus, statsd, ...) without
+/// forking the crate. Every method has a no-op default, so implementors
+/// only need to override the events they care about.
+pub trait Instrumentation: Send + Sync {
+    /// A new connection was established.
+    fn on_connect(&self) {}
+
+    /// Establishing a new connection failed.
+    fn on_connect_failed(&self, _err: &RedisError) {}
+
+    /// A connection was successfully checked out of the pool.
+    fn on_checkout(&self) {}
+
+    /// How long a checkout waited before a connection became available.
+    fn on_checkout_wait(&self, _wait: Duration) {}
+
+    /// `is_valid` rejected a connection.
+    fn on_is_valid_failed(&self, _err: &RedisError) {}
+
+    /// A connection was dropped instead of being returned to the pool.
+    fn on_connection_dropped(&self) {}
+}
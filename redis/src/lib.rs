@@ -7,6 +7,7 @@ pub extern crate redis;
 extern crate futures;
 extern crate tokio;
 
+use futures::sync::oneshot;
 use futures::{Future, IntoFuture};
 
 use redis::async::Connection;
@@ -14,21 +15,57 @@ use redis::{Client, RedisError};
 
 use std::io;
 use std::option::Option;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod instrumentation;
+mod multiplexed_connection_manager;
+mod pooled_connection;
+mod replica_pool;
+
+pub use instrumentation::Instrumentation;
+pub use multiplexed_connection_manager::RedisMultiplexedConnectionManager;
+pub use pooled_connection::PooledConnection;
+pub use replica_pool::ReplicaRedisPool;
 
 type Result<T> = std::result::Result<T, RedisError>;
 
 /// `RedisPool` is a convenience wrapper around `bb8::Pool` that hides the fact that
 /// `RedisConnectionManager` uses an `Option<Connection>` to smooth over the API incompatibility.
-#[derive(Debug)]
 pub struct RedisPool {
     pool: bb8::Pool<RedisConnectionManager>,
+    instrumentation: Option<Arc<Instrumentation>>,
+}
+
+impl ::std::fmt::Debug for RedisPool {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("RedisPool")
+            .field("pool", &self.pool)
+            .field("instrumentation", &self.instrumentation.is_some())
+            .finish()
+    }
 }
 
 impl RedisPool {
     /// Constructs a new `RedisPool`, see the `bb8::Builder` documentation for description of
     /// parameters.
     pub fn new(pool: bb8::Pool<RedisConnectionManager>) -> RedisPool {
-        RedisPool { pool }
+        RedisPool {
+            pool,
+            instrumentation: None,
+        }
+    }
+
+    /// Constructs a new `RedisPool` that reports checkout events (`on_checkout`,
+    /// `on_checkout_wait`) to the given `Instrumentation`.
+    pub fn with_instrumentation(
+        pool: bb8::Pool<RedisConnectionManager>,
+        instrumentation: Arc<Instrumentation>,
+    ) -> RedisPool {
+        RedisPool {
+            pool,
+            instrumentation: Some(instrumentation),
+        }
     }
 
     /// Run the function with a connection provided by the pool.
@@ -40,16 +77,65 @@ impl RedisPool {
         E: From<RedisError> + Send + 'a,
         T: Send + 'a,
     {
-        let f = move |conn: Option<Connection>| {
-            let conn = conn.unwrap();
+        let instrumentation = self.instrumentation.clone();
+        let waited_since = Instant::now();
+        let f = move |conn: Option<(Connection, Instant)>| {
+            if let Some(ref instrumentation) = instrumentation {
+                instrumentation.on_checkout_wait(waited_since.elapsed());
+                instrumentation.on_checkout();
+            }
+            let (conn, created_at) = conn.unwrap();
             f(conn)
                 .into_future()
-                .map(|(conn, item)| (item, Some(conn)))
+                .map(move |(conn, item)| (item, Some((conn, created_at))))
                 .map_err(|err| (err, None))
         };
         self.pool.run(f)
     }
 
+    /// Check out a connection from the pool.
+    ///
+    /// Unlike `run`, the returned `PooledConnection` implements redis-rs's
+    /// `ConnectionLike`, so it can be used directly with `redis::cmd(...)`
+    /// or the `AsyncCommands` trait instead of a closure. The connection is
+    /// returned to the pool when the `PooledConnection` is dropped.
+    ///
+    /// Like `run` and `dedicated_connection`, nothing happens until the
+    /// returned future is polled: the checkout (and the background task
+    /// that keeps it alive) is only started then, not when `get` is called.
+    pub fn get(&self) -> impl Future<Item = PooledConnection, Error = RedisError> + Send {
+        let pool = self.pool.clone();
+        let instrumentation = self.instrumentation.clone();
+
+        futures::future::lazy(move || {
+            let (checkout_tx, checkout_rx) = oneshot::channel();
+            let (release_tx, release_rx) = oneshot::channel::<Option<(Connection, Instant)>>();
+
+            let waited_since = Instant::now();
+            let checked_out = pool.run(move |conn: Option<(Connection, Instant)>| {
+                if let Some(ref instrumentation) = instrumentation {
+                    instrumentation.on_checkout_wait(waited_since.elapsed());
+                    instrumentation.on_checkout();
+                }
+                let conn = conn.expect("RedisConnectionManager connections are always Some");
+                // Hand the connection to the caller and keep this `run` future
+                // alive until the `PooledConnection` is dropped and gives it
+                // back, so the pool only considers the slot free again then.
+                let _ = checkout_tx.send(conn);
+                release_rx.then(|res| match res {
+                    Ok(conn) => Ok(((), conn)),
+                    Err(_) => Err((pooled_connection::release_error(), None)),
+                })
+            });
+
+            tokio::spawn(checked_out.map_err(|_: RedisError| ()));
+
+            checkout_rx
+                .map_err(|_| pooled_connection::checkout_error())
+                .map(move |(conn, created_at)| PooledConnection::new(conn, created_at, release_tx))
+        })
+    }
+
     /// Get a new dedicated connection that will not be managed by the pool.
     /// An application may want a persistent connection
     /// that will not be closed or repurposed by the pool.
@@ -59,9 +145,11 @@ impl RedisPool {
     pub fn dedicated_connection(
         &self,
     ) -> impl Future<Item = Connection, Error = RedisError> + Send {
-        self.pool.dedicated_connection()
-            .map(|opt_con|
-                opt_con.expect("Couldn't get a dedicated Redis connection!"))
+        self.pool.dedicated_connection().map(|opt_con| {
+            opt_con
+                .expect("Couldn't get a dedicated Redis connection!")
+                .0
+        })
     }
     /// Returns information about the current state of the pool.
     pub fn state(&self) -> bb8::State {
@@ -70,26 +158,98 @@ impl RedisPool {
 }
 
 /// A `bb8::ManageConnection` for `redis::async::Connection`s.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct RedisConnectionManager {
     client: Client,
+    max_connection_age: Option<Duration>,
+    check_on_checkout: bool,
+    instrumentation: Option<Arc<Instrumentation>>,
+}
+
+impl ::std::fmt::Debug for RedisConnectionManager {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("RedisConnectionManager")
+            .field("client", &self.client)
+            .field("max_connection_age", &self.max_connection_age)
+            .field("check_on_checkout", &self.check_on_checkout)
+            .field("instrumentation", &self.instrumentation.is_some())
+            .finish()
+    }
 }
 
 impl RedisConnectionManager {
     /// Create a new `RedisConnectionManager`.
     pub fn new(client: Client) -> Result<RedisConnectionManager> {
-        Ok(RedisConnectionManager { client })
+        Ok(RedisConnectionManager {
+            client,
+            max_connection_age: None,
+            check_on_checkout: true,
+            instrumentation: None,
+        })
+    }
+
+    /// Report `connect` and `is_valid` events to the given `Instrumentation`.
+    pub fn set_instrumentation(
+        mut self,
+        instrumentation: Arc<Instrumentation>,
+    ) -> RedisConnectionManager {
+        self.instrumentation = Some(instrumentation);
+        self
+    }
+
+    /// Cap how long a connection may live before `has_broken` reports it
+    /// broken and the pool replaces it with a fresh one.
+    ///
+    /// Useful when servers sit behind a load balancer or close idle sockets
+    /// server-side after some timeout: without this, the pool would keep
+    /// handing out connections the server has already dropped.
+    pub fn set_max_connection_age(
+        mut self,
+        max_connection_age: Duration,
+    ) -> RedisConnectionManager {
+        self.max_connection_age = Some(max_connection_age);
+        self
+    }
+
+    /// Control whether `is_valid` sends a `PING` on every checkout.
+    ///
+    /// Defaults to `true`. Disabling this trades correctness for latency:
+    /// checkouts skip the round-trip and resolve as valid immediately, so a
+    /// connection the server already closed may be handed out and only
+    /// discovered broken when a command on it fails. Callers who prefer to
+    /// handle that via `has_broken`/retry instead of paying a `PING` on
+    /// every checkout should set this to `false`.
+    pub fn set_check_on_checkout(mut self, check_on_checkout: bool) -> RedisConnectionManager {
+        self.check_on_checkout = check_on_checkout;
+        self
     }
 }
 
 impl bb8::ManageConnection for RedisConnectionManager {
-    type Connection = Option<Connection>;
+    type Connection = Option<(Connection, Instant)>;
     type Error = RedisError;
 
     fn connect(
         &self,
     ) -> Box<Future<Item = Self::Connection, Error = Self::Error> + Send + 'static> {
-        Box::new(self.client.get_async_connection().map(|conn| Some(conn)))
+        let instrumentation = self.instrumentation.clone();
+        let instrumentation_on_err = instrumentation.clone();
+        Box::new(
+            self.client
+                .get_async_connection()
+                .map(move |conn| {
+                    if let Some(instrumentation) = instrumentation {
+                        instrumentation.on_connect();
+                    }
+                    Some((conn, Instant::now()))
+                })
+                .map_err(move |err| {
+                    if let Some(instrumentation) = instrumentation_on_err {
+                        instrumentation.on_connect_failed(&err);
+                    }
+                    err
+                }),
+        )
     }
 
     fn is_valid(
@@ -97,19 +257,71 @@ impl bb8::ManageConnection for RedisConnectionManager {
         conn: Self::Connection,
     ) -> Box<Future<Item = Self::Connection, Error = (Self::Error, Self::Connection)> + Send> {
         // The connection should only be None after a failure.
+        let (conn, created_at) = conn.unwrap();
+        if !self.check_on_checkout {
+            return Box::new(futures::future::ok(Some((conn, created_at))));
+        }
+        let instrumentation = self.instrumentation.clone();
         Box::new(
             redis::cmd("PING")
-                .query_async(conn.unwrap())
-                .map_err(|err| (err, None))
-                .map(|(conn, ())| Some(conn)),
+                .query_async(conn)
+                .map_err(move |err| {
+                    if let Some(instrumentation) = instrumentation {
+                        instrumentation.on_is_valid_failed(&err);
+                    }
+                    (err, None)
+                })
+                .map(move |(conn, ())| Some((conn, created_at))),
         )
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.is_none()
+        let broken = match conn {
+            None => true,
+            Some((_, created_at)) => is_expired(*created_at, self.max_connection_age),
+        };
+        if broken {
+            if let Some(ref instrumentation) = self.instrumentation {
+                instrumentation.on_connection_dropped();
+            }
+        }
+        broken
     }
 
     fn timed_out(&self) -> Self::Error {
         io::Error::new(io::ErrorKind::TimedOut, "RedisConnectionManager timed out").into()
     }
 }
+
+/// Whether a connection created at `created_at` has outlived `max_connection_age`.
+/// A `None` age means connections never expire on their own.
+fn is_expired(created_at: Instant, max_connection_age: Option<Duration>) -> bool {
+    match max_connection_age {
+        Some(max_connection_age) => created_at.elapsed() >= max_connection_age,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_expired;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn is_expired_never_expires_without_a_max_age() {
+        assert!(!is_expired(Instant::now(), None));
+    }
+
+    #[test]
+    fn is_expired_false_before_the_max_age_elapses() {
+        assert!(!is_expired(Instant::now(), Some(Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn is_expired_true_once_the_max_age_elapses() {
+        let created_at = Instant::now();
+        thread::sleep(Duration::from_millis(20));
+        assert!(is_expired(created_at, Some(Duration::from_millis(10))));
+    }
+}
@@ -0,0 +1,126 @@
+use futures::sync::oneshot;
+use futures::Future;
+
+use redis::async::Connection;
+use redis::{ConnectionLike, RedisError, Value};
+
+use std::io;
+use std::time::Instant;
+
+/// A connection checked out of a [`RedisPool`](struct.RedisPool.html).
+///
+/// Unlike the closures passed to `RedisPool::run`, a `PooledConnection`
+/// implements redis-rs's `ConnectionLike`, so it can be passed directly to
+/// `redis::cmd(...).query_async(conn)` or the `AsyncCommands` helper
+/// methods. The underlying connection is returned to the pool when the
+/// `PooledConnection` is dropped; if the connection was left mid-command
+/// (the command future errored or was dropped before completing) it is
+/// discarded instead of being recycled.
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    created_at: Instant,
+    connection_state_ok: bool,
+    release: Option<oneshot::Sender<Option<(Connection, Instant)>>>,
+}
+
+impl ::std::fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("PooledConnection")
+            .field("conn", &self.conn.is_some())
+            .field("created_at", &self.created_at)
+            .field("connection_state_ok", &self.connection_state_ok)
+            .finish()
+    }
+}
+
+impl PooledConnection {
+    pub(crate) fn new(
+        conn: Connection,
+        created_at: Instant,
+        release: oneshot::Sender<Option<(Connection, Instant)>>,
+    ) -> PooledConnection {
+        PooledConnection {
+            conn: Some(conn),
+            created_at,
+            connection_state_ok: true,
+            release: Some(release),
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            let conn = if self.connection_state_ok {
+                self.conn.take().map(|conn| (conn, self.created_at))
+            } else {
+                None
+            };
+            // The other end of the channel is the future driving the
+            // checkout; if it's already gone the pool has nothing to
+            // recycle into anyway.
+            let _ = release.send(conn);
+        }
+    }
+}
+
+impl ConnectionLike for PooledConnection {
+    fn req_packed_command(
+        mut self,
+        cmd: Vec<u8>,
+    ) -> Box<Future<Item = (Self, Value), Error = RedisError> + Send> {
+        let conn = self
+            .conn
+            .take()
+            .expect("PooledConnection used after a previous command left it broken");
+        // Assume the worst until the command comes back clean; this way a
+        // dropped-mid-flight future also leaves the connection marked broken.
+        self.connection_state_ok = false;
+        Box::new(conn.req_packed_command(cmd).map(move |(conn, value)| {
+            self.conn = Some(conn);
+            self.connection_state_ok = true;
+            (self, value)
+        }))
+    }
+
+    fn req_packed_commands(
+        mut self,
+        cmd: Vec<u8>,
+        offset: usize,
+        count: usize,
+    ) -> Box<Future<Item = (Self, Vec<Value>), Error = RedisError> + Send> {
+        let conn = self
+            .conn
+            .take()
+            .expect("PooledConnection used after a previous command left it broken");
+        self.connection_state_ok = false;
+        Box::new(
+            conn.req_packed_commands(cmd, offset, count)
+                .map(move |(conn, values)| {
+                    self.conn = Some(conn);
+                    self.connection_state_ok = true;
+                    (self, values)
+                }),
+        )
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map(Connection::get_db).unwrap_or(0)
+    }
+}
+
+pub(crate) fn checkout_error() -> RedisError {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "RedisPool::get: pool could not provide a connection",
+    )
+    .into()
+}
+
+pub(crate) fn release_error() -> RedisError {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "PooledConnection dropped without returning a connection",
+    )
+    .into()
+}
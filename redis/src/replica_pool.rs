@@ -0,0 +1,280 @@
+use futures::{Future, IntoFuture};
+
+use redis::async::Connection;
+use redis::RedisError;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use RedisPool;
+
+/// How long a replica that failed validation is left out of the read
+/// rotation before it's given another chance.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Tracks whether a replica is currently in the read rotation, independent
+/// of any pool/connection state, so the cooldown bookkeeping can be unit
+/// tested without a live Redis server.
+struct ReplicaState {
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl ReplicaState {
+    fn new() -> ReplicaState {
+        ReplicaState {
+            down_until: Mutex::new(None),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        match *self.down_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    fn mark_down(&self, retry_after: Duration) {
+        *self.down_until.lock().unwrap() = Some(Instant::now() + retry_after);
+    }
+
+    fn mark_up(&self) {
+        *self.down_until.lock().unwrap() = None;
+    }
+}
+
+struct Replica {
+    pool: RedisPool,
+    state: ReplicaState,
+}
+
+impl Replica {
+    fn new(pool: RedisPool) -> Replica {
+        Replica {
+            pool,
+            state: ReplicaState::new(),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.state.is_available()
+    }
+
+    fn mark_down(&self, retry_after: Duration) {
+        self.state.mark_down(retry_after);
+    }
+
+    fn mark_up(&self) {
+        self.state.mark_up();
+    }
+
+    /// `PING` the replica directly, independent of whatever error type the
+    /// caller's own closure produces. This is the only signal `run_read`
+    /// uses to decide whether a replica is actually unhealthy.
+    fn probe_health(&self) -> impl Future<Item = (), Error = RedisError> + Send {
+        self.pool.run(|conn| redis::cmd("PING").query_async(conn))
+    }
+}
+
+impl ::std::fmt::Debug for Replica {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Replica")
+            .field("pool", &self.pool)
+            .field("available", &self.is_available())
+            .finish()
+    }
+}
+
+/// Picks the next available index out of `len` slots, starting from
+/// `cursor` and advancing it round-robin, skipping slots `is_available`
+/// reports as down. Returns `None` if `len` is zero or every slot is down.
+fn select_available(
+    cursor: &AtomicUsize,
+    len: usize,
+    is_available: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+
+    (0..len)
+        .map(|_| cursor.fetch_add(1, Ordering::Relaxed) % len)
+        .find(|&index| is_available(index))
+}
+
+/// A pool that routes writes to a primary Redis node and round-robins reads
+/// across a set of read replicas, giving horizontal read scaling from a
+/// single pool abstraction instead of juggling multiple `RedisPool`s by hand.
+///
+/// Replicas that fail a `PING` health check after a read errors are
+/// temporarily taken out of the read rotation and retried again after a
+/// cooldown, so a single unhealthy replica doesn't
+/// keep failing every read that round-robins onto it.
+#[derive(Debug)]
+pub struct ReplicaRedisPool {
+    primary: RedisPool,
+    replicas: Vec<Arc<Replica>>,
+    next_replica: AtomicUsize,
+    retry_after: Duration,
+}
+
+impl ReplicaRedisPool {
+    /// Construct a `ReplicaRedisPool` from a primary pool and its replicas,
+    /// using the default 30 second cooldown before a failed replica is
+    /// retried. See `with_retry_after` to configure the cooldown.
+    pub fn new(primary: RedisPool, replicas: Vec<RedisPool>) -> ReplicaRedisPool {
+        ReplicaRedisPool::with_retry_after(primary, replicas, DEFAULT_RETRY_AFTER)
+    }
+
+    /// Construct a `ReplicaRedisPool`, configuring how long a replica that
+    /// failed its health check after a read error is excluded from the
+    /// rotation before being retried.
+    pub fn with_retry_after(
+        primary: RedisPool,
+        replicas: Vec<RedisPool>,
+        retry_after: Duration,
+    ) -> ReplicaRedisPool {
+        ReplicaRedisPool {
+            primary,
+            replicas: replicas
+                .into_iter()
+                .map(Replica::new)
+                .map(Arc::new)
+                .collect(),
+            next_replica: AtomicUsize::new(0),
+            retry_after,
+        }
+    }
+
+    /// Run the function against the primary pool. Use this for writes, and
+    /// for reads that must observe the primary's up-to-date state.
+    pub fn run_write<'a, T, E, U, F>(&self, f: F) -> impl Future<Item = T, Error = E> + Send + 'a
+    where
+        F: FnOnce(Connection) -> U + Send + 'a,
+        U: IntoFuture<Item = (Connection, T), Error = E> + 'a,
+        U::Future: Send,
+        E: From<RedisError> + Send + 'a,
+        T: Send + 'a,
+    {
+        self.primary.run(f)
+    }
+
+    /// Run the function against the next available replica in the
+    /// round-robin rotation, falling back to the primary if every replica
+    /// is currently marked down.
+    ///
+    /// A replica is only pulled from rotation when a direct `PING` against
+    /// it fails after `f` errors. The caller's own error type `E` is
+    /// application-defined (a "not found", a decode error, ...) and is not
+    /// by itself treated as a sign the replica is unhealthy, so routine
+    /// business-logic errors don't take a perfectly healthy replica out of
+    /// the read rotation.
+    pub fn run_read<'a, T, E, U, F>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'a>
+    where
+        F: FnOnce(Connection) -> U + Send + 'a,
+        U: IntoFuture<Item = (Connection, T), Error = E> + 'a,
+        U::Future: Send,
+        E: From<RedisError> + Send + 'a,
+        T: Send + 'a,
+    {
+        // An owned `Arc<Replica>` clone is captured by the closures below
+        // instead of a `&Replica` borrowed from `self`, so the returned
+        // future has no dependency on `self`'s lifetime.
+        let replica = match self.next_available_replica() {
+            Some(replica) => replica,
+            None => return Box::new(self.primary.run(f)),
+        };
+
+        let retry_after = self.retry_after;
+        Box::new(
+            replica
+                .pool
+                .run(f)
+                .then(move |res| -> Box<Future<Item = T, Error = E> + Send> {
+                    match res {
+                        Ok(item) => {
+                            replica.mark_up();
+                            Box::new(Ok(item).into_future())
+                        }
+                        Err(err) => Box::new(replica.probe_health().then(move |health| {
+                            if health.is_err() {
+                                replica.mark_down(retry_after);
+                            }
+                            Err(err)
+                        })),
+                    }
+                }),
+        )
+    }
+
+    fn next_available_replica(&self) -> Option<Arc<Replica>> {
+        let replicas = &self.replicas;
+        select_available(&self.next_replica, replicas.len(), |index| {
+            replicas[index].is_available()
+        })
+        .map(|index| replicas[index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_available, ReplicaState};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn select_available_round_robins_when_all_up() {
+        let cursor = AtomicUsize::new(0);
+        let picks: Vec<usize> = (0..4)
+            .map(|_| select_available(&cursor, 3, |_| true).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn select_available_skips_down_slots() {
+        let cursor = AtomicUsize::new(0);
+        let down = [false, true, false];
+        let picks: Vec<usize> = (0..3)
+            .map(|_| select_available(&cursor, down.len(), |index| !down[index]).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 2, 0]);
+    }
+
+    #[test]
+    fn select_available_returns_none_when_empty() {
+        let cursor = AtomicUsize::new(0);
+        assert_eq!(select_available(&cursor, 0, |_| true), None);
+    }
+
+    #[test]
+    fn select_available_returns_none_when_everything_down() {
+        let cursor = AtomicUsize::new(0);
+        assert_eq!(select_available(&cursor, 3, |_| false), None);
+    }
+
+    #[test]
+    fn replica_state_starts_available() {
+        let state = ReplicaState::new();
+        assert!(state.is_available());
+    }
+
+    #[test]
+    fn replica_state_mark_down_then_up() {
+        let state = ReplicaState::new();
+        state.mark_down(Duration::from_secs(60));
+        assert!(!state.is_available());
+        state.mark_up();
+        assert!(state.is_available());
+    }
+
+    #[test]
+    fn replica_state_becomes_available_after_cooldown_elapses() {
+        let state = ReplicaState::new();
+        state.mark_down(Duration::from_millis(20));
+        assert!(!state.is_available());
+        thread::sleep(Duration::from_millis(40));
+        assert!(state.is_available());
+    }
+}